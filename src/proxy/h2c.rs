@@ -0,0 +1,606 @@
+//! Support for the RFC 7540 §3.2 HTTP/2-over-cleartext upgrade dance: a
+//! client speaks HTTP/1.1 with `Connection: Upgrade`, `Upgrade: h2c`, and
+//! a base64 `HTTP2-Settings` header, and the server switches the
+//! connection straight into HTTP/2 without ever doing a TLS handshake.
+//!
+//! This is distinct from (and complements) prior-knowledge detection in
+//! `proxy::protocol::Protocol::detect`, which only recognizes clients
+//! that skip HTTP/1 altogether. Gated per-listener by the server's
+//! `h2c_upgrade_ports`, since it's only safe to honor on trusted
+//! plaintext ports — mirroring how actix's `tcp_auto_h2c` is itself an
+//! opt-in.
+//!
+//! Per §3.2, a client that sends a request body does so using ordinary
+//! HTTP/1.1 framing (`Content-Length` or chunked) *before* it knows
+//! whether the upgrade succeeded, immediately followed by its real
+//! HTTP/2 connection preface and frames. `Replay` is what reshapes that
+//! wire format into what an HTTP/2 server expects to read: the synthetic
+//! preface/SETTINGS/HEADERS from `detect`, the body re-framed as DATA on
+//! stream 1, and then the client's real (and, by now, redundant) preface
+//! skipped before the rest of the connection is passed through untouched.
+
+use base64;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use httparse;
+use std::io;
+use tokio_io::{AsyncRead, AsyncWrite};
+use futures::Poll;
+
+/// The client connection preface every HTTP/2 connection begins with.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_HEADERS: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_END_STREAM: u8 = 0x1;
+
+/// The largest DATA frame payload we'll emit for a single chunk of
+/// replayed body, matching the default `SETTINGS_MAX_FRAME_SIZE`.
+const MAX_FRAME_SIZE: usize = 16_384;
+
+/// How the replayed HTTP/1.1 request indicated its body is framed, so
+/// `Replay` knows how to find the end of it on the wire.
+#[derive(Clone, Debug, PartialEq)]
+enum BodyMode {
+    /// No body: e.g. a GET/HEAD, or a request with neither
+    /// `Content-Length` nor `Transfer-Encoding: chunked`.
+    None,
+    /// A body of exactly this many bytes follows, raw.
+    Fixed(u64),
+    /// A `Transfer-Encoding: chunked` body follows.
+    Chunked,
+}
+
+/// An HTTP/1.1 request asking to upgrade to h2c.
+#[derive(Debug)]
+pub struct Upgrade {
+    /// The preface, SETTINGS frame (from the decoded `HTTP2-Settings`
+    /// header), and HEADERS frame (the replayed request) to serve ahead
+    /// of the connection.
+    prefix: Bytes,
+    body: BodyMode,
+    /// Whether the replayed HEADERS frame already carries `END_STREAM`
+    /// (true when there's no body to follow).
+    end_stream: bool,
+    /// How many bytes of the real connection the replayed HTTP/1.1
+    /// request head took up. Those bytes were only ever peeked, never
+    /// consumed, so the caller still needs to skip them before handing
+    /// the connection to `Replay` — otherwise they'd be read a second
+    /// time as if they were the request body or the client's preface.
+    head_len: usize,
+}
+
+impl Upgrade {
+    /// See the `head_len` field.
+    pub fn head_len(&self) -> usize {
+        self.head_len
+    }
+}
+
+/// Inspects the head of a buffered HTTP/1.1 request for the h2c upgrade
+/// headers. Returns `None` if the request is well-formed but isn't
+/// asking to upgrade (or the buffered bytes don't yet contain a
+/// complete request head) — the caller should fall back to ordinary
+/// HTTP/1 handling.
+pub fn detect(buf: &[u8]) -> Option<Upgrade> {
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut req = httparse::Request::new(&mut headers);
+    let head_len = match req.parse(buf) {
+        Ok(httparse::Status::Complete(n)) => n,
+        _ => return None,
+    };
+
+    let mut wants_upgrade = false;
+    let mut is_h2c = false;
+    let mut settings = None;
+    let mut content_length = None;
+    let mut chunked = false;
+    for header in req.headers.iter() {
+        if header.name.eq_ignore_ascii_case("connection") {
+            wants_upgrade = wants_upgrade
+                || header
+                    .value
+                    .split(|&b| b == b',')
+                    .any(|token| trim(token).eq_ignore_ascii_case(b"upgrade"));
+        } else if header.name.eq_ignore_ascii_case("upgrade") {
+            is_h2c = trim(header.value).eq_ignore_ascii_case(b"h2c");
+        } else if header.name.eq_ignore_ascii_case("http2-settings") {
+            settings = base64::decode_config(header.value, base64::URL_SAFE_NO_PAD).ok();
+        } else if header.name.eq_ignore_ascii_case("content-length") {
+            content_length = ::std::str::from_utf8(header.value)
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok());
+        } else if header.name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = header
+                .value
+                .split(|&b| b == b',')
+                .any(|token| trim(token).eq_ignore_ascii_case(b"chunked"));
+        }
+    }
+
+    if !wants_upgrade || !is_h2c {
+        return None;
+    }
+    let settings = settings?;
+    let method = req.method?;
+    let path = req.path?;
+    let authority = req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("host"))
+        .map(|h| h.value)
+        .unwrap_or(b"");
+
+    let body = if chunked {
+        BodyMode::Chunked
+    } else if let Some(len) = content_length {
+        if len == 0 {
+            BodyMode::None
+        } else {
+            BodyMode::Fixed(len)
+        }
+    } else {
+        BodyMode::None
+    };
+    let end_stream = body == BodyMode::None;
+
+    let mut prefix = BytesMut::with_capacity(PREFACE.len() + 9 + settings.len() + 64);
+    prefix.put_slice(PREFACE);
+    write_frame(&mut prefix, FRAME_SETTINGS, 0, 0, &settings);
+
+    let mut block = BytesMut::new();
+    write_literal(&mut block, b":method", method.as_bytes());
+    write_literal(&mut block, b":scheme", b"http");
+    write_literal(&mut block, b":authority", authority);
+    write_literal(&mut block, b":path", path.as_bytes());
+    for h in req.headers.iter() {
+        if h.name.eq_ignore_ascii_case("connection")
+            || h.name.eq_ignore_ascii_case("upgrade")
+            || h.name.eq_ignore_ascii_case("http2-settings")
+            || h.name.eq_ignore_ascii_case("host")
+            || h.name.eq_ignore_ascii_case("transfer-encoding")
+            || h.name.eq_ignore_ascii_case("content-length")
+        {
+            // Hop-by-hop or folded into the pseudo-headers above, or (for
+            // the two framing headers) forbidden in an HTTP/2 request per
+            // RFC 7540 §8.1.2.2 now that the body is carried as DATA
+            // frames instead.
+            continue;
+        }
+        write_literal(&mut block, h.name.to_ascii_lowercase().as_bytes(), h.value);
+    }
+
+    let flags = if end_stream {
+        FLAG_END_HEADERS | FLAG_END_STREAM
+    } else {
+        FLAG_END_HEADERS
+    };
+    write_frame(&mut prefix, FRAME_HEADERS, flags, 1, &block);
+
+    Some(Upgrade {
+        prefix: prefix.freeze(),
+        body,
+        end_stream,
+        head_len,
+    })
+}
+
+fn trim(buf: &[u8]) -> &[u8] {
+    let start = buf.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(buf.len());
+    let end = buf
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map(|i| i + 1)
+        .unwrap_or(start);
+    &buf[start..end]
+}
+
+fn write_frame(out: &mut BytesMut, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    let len = payload.len() as u32;
+    out.put_u8((len >> 16) as u8);
+    out.put_u8((len >> 8) as u8);
+    out.put_u8(len as u8);
+    out.put_u8(kind);
+    out.put_u8(flags);
+    out.put_u32_be(stream_id & 0x7FFF_FFFF);
+    out.put_slice(payload);
+}
+
+/// Encodes one header as an HPACK "Literal Header Field Never Indexed —
+/// New Name" (a literal, Huffman-free representation valid for any
+/// header, even though it forgoes the static table's usual savings).
+fn write_literal(out: &mut BytesMut, name: &[u8], value: &[u8]) {
+    out.put_u8(0x10);
+    write_hpack_string(out, name);
+    write_hpack_string(out, value);
+}
+
+fn write_hpack_string(out: &mut BytesMut, s: &[u8]) {
+    // Huffman-free, so the H bit of the length prefix is always 0.
+    write_hpack_int(out, 0x00, s.len());
+    out.put_slice(s);
+}
+
+/// Encodes `value` as an HPACK integer with a 7-bit prefix (RFC 7541
+/// §5.1), the form used by a string literal's length — `prefix_flags` is
+/// ORed into the first byte alongside the (up to 7-bit) prefix value.
+/// Unlike a fixed single-byte length, this has no upper bound: ordinary
+/// client input like a long URL, cookie, or `Host` header can easily
+/// exceed 126 bytes, and truncating the length there would corrupt
+/// everything that follows in the HEADERS block.
+fn write_hpack_int(out: &mut BytesMut, prefix_flags: u8, value: usize) {
+    const PREFIX_MAX: usize = (1 << 7) - 1;
+    if value < PREFIX_MAX {
+        out.put_u8(prefix_flags | value as u8);
+        return;
+    }
+    out.put_u8(prefix_flags | PREFIX_MAX as u8);
+    let mut value = value - PREFIX_MAX;
+    while value >= 0x80 {
+        out.put_u8(((value & 0x7F) | 0x80) as u8);
+        value >>= 7;
+    }
+    out.put_u8(value as u8);
+}
+
+/// The phase `Replay` is in: first the synthesized prefix, then (if the
+/// request had a body) re-framing that body as DATA on stream 1, then
+/// discarding the client's real, now-redundant connection preface, and
+/// finally a plain passthrough of whatever the client sends next.
+enum Phase {
+    Prefix(usize),
+    Body,
+    SkipPreface(usize),
+    Passthrough,
+}
+
+/// A chunked-transfer decoder that tracks just enough state to resume
+/// across non-blocking reads that land mid-chunk-size-line or mid-chunk.
+enum ChunkState {
+    Size(Vec<u8>),
+    Data(u64),
+    DataCrlf(usize),
+    TrailerCrlf(Vec<u8>),
+    Done,
+}
+
+/// Wraps an h2c-upgraded `Io`, rewriting the wire bytes into what an
+/// HTTP/2 server expects to read — see the module docs for why this is
+/// more than a simple byte prepend.
+pub struct Replay<T> {
+    out: BytesMut,
+    phase: Phase,
+    body: BodyMode,
+    chunk: ChunkState,
+    inner: T,
+}
+
+impl<T> Replay<T> {
+    pub fn new(upgrade: Upgrade, inner: T) -> Self {
+        let prefix_len = upgrade.prefix.len();
+        let mut out = BytesMut::new();
+        out.extend_from_slice(&upgrade.prefix);
+        Replay {
+            out,
+            phase: Phase::Prefix(prefix_len),
+            body: upgrade.body,
+            chunk: ChunkState::Size(Vec::new()),
+            inner,
+        }
+    }
+}
+
+impl<T: io::Read> Replay<T> {
+    /// Pulls more data from `inner`, advancing `self.phase` and filling
+    /// `self.out` with whatever should be handed to the reader next.
+    /// Returns `Ok(false)` (having read nothing into `out`) only at a
+    /// clean EOF; propagates `WouldBlock` so the caller can retry later
+    /// without losing any partially-decoded state.
+    fn fill(&mut self) -> io::Result<bool> {
+        loop {
+            match self.phase {
+                Phase::Prefix(_) => {
+                    // Already served directly out of `out` by `new`;
+                    // once drained, move on.
+                    self.phase = match self.body {
+                        BodyMode::None => Phase::SkipPreface(PREFACE.len()),
+                        _ => Phase::Body,
+                    };
+                }
+                Phase::Body => {
+                    let mut scratch = [0u8; 4096];
+                    match self.body.clone() {
+                        BodyMode::Fixed(remaining) => {
+                            if remaining == 0 {
+                                write_frame(&mut self.out, FRAME_DATA, FLAG_END_STREAM, 1, &[]);
+                                self.phase = Phase::SkipPreface(PREFACE.len());
+                                return Ok(true);
+                            }
+                            let max = ::std::cmp::min(scratch.len() as u64, remaining) as usize;
+                            let n = self.inner.read(&mut scratch[..max])?;
+                            if n == 0 {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::UnexpectedEof,
+                                    "connection closed mid h2c-upgrade body",
+                                ));
+                            }
+                            let remaining = remaining - n as u64;
+                            self.body = BodyMode::Fixed(remaining);
+                            let flags = if remaining == 0 { FLAG_END_STREAM } else { 0 };
+                            write_frame(&mut self.out, FRAME_DATA, flags, 1, &scratch[..n]);
+                            if remaining == 0 {
+                                self.phase = Phase::SkipPreface(PREFACE.len());
+                            }
+                            return Ok(true);
+                        }
+                        BodyMode::Chunked => {
+                            if self.decode_chunk(&mut scratch)? {
+                                self.phase = Phase::SkipPreface(PREFACE.len());
+                            }
+                            return Ok(true);
+                        }
+                        BodyMode::None => unreachable!("Body phase implies a body"),
+                    }
+                }
+                Phase::SkipPreface(remaining) => {
+                    if remaining == 0 {
+                        self.phase = Phase::Passthrough;
+                        continue;
+                    }
+                    let mut scratch = [0u8; 64];
+                    let max = ::std::cmp::min(scratch.len(), remaining);
+                    let n = self.inner.read(&mut scratch[..max])?;
+                    if n == 0 {
+                        return Ok(false);
+                    }
+                    self.phase = Phase::SkipPreface(remaining - n);
+                    // The client's own preface is discarded — we already
+                    // gave the HTTP/2 server an equivalent one.
+                    continue;
+                }
+                Phase::Passthrough => {
+                    let mut scratch = [0u8; 4096];
+                    let n = self.inner.read(&mut scratch)?;
+                    if n == 0 {
+                        return Ok(false);
+                    }
+                    self.out.extend_from_slice(&scratch[..n]);
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    /// Reads and dechunks one step of a `Transfer-Encoding: chunked`
+    /// body, appending any decoded bytes to `self.out` as one or more
+    /// DATA frames. Returns `Ok(true)` once the terminating zero-length
+    /// chunk (and its trailer) has been consumed.
+    fn decode_chunk(&mut self, scratch: &mut [u8]) -> io::Result<bool> {
+        loop {
+            match self.chunk {
+                ChunkState::Size(ref mut line) => {
+                    let mut byte = [0u8; 1];
+                    let n = self.inner.read(&mut byte)?;
+                    if n == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "connection closed mid chunk-size line",
+                        ));
+                    }
+                    line.push(byte[0]);
+                    if line.ends_with(b"\r\n") {
+                        let digits = &line[..line.len() - 2];
+                        // Ignore chunk extensions after `;`.
+                        let digits = digits.split(|&b| b == b';').next().unwrap_or(digits);
+                        let size = ::std::str::from_utf8(digits)
+                            .ok()
+                            .and_then(|s| u64::from_str_radix(s.trim(), 16).ok())
+                            .ok_or_else(|| {
+                                io::Error::new(io::ErrorKind::InvalidData, "bad chunk size")
+                            })?;
+                        self.chunk = if size == 0 {
+                            ChunkState::TrailerCrlf(Vec::new())
+                        } else {
+                            ChunkState::Data(size)
+                        };
+                    }
+                }
+                ChunkState::Data(remaining) => {
+                    let max = ::std::cmp::min(scratch.len() as u64, remaining) as usize;
+                    let n = self.inner.read(&mut scratch[..max])?;
+                    if n == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "connection closed mid chunk data",
+                        ));
+                    }
+                    write_frame(&mut self.out, FRAME_DATA, 0, 1, &scratch[..n]);
+                    self.chunk = if remaining - n as u64 == 0 {
+                        ChunkState::DataCrlf(2)
+                    } else {
+                        ChunkState::Data(remaining - n as u64)
+                    };
+                    return Ok(false);
+                }
+                ChunkState::DataCrlf(left) => {
+                    let mut byte = [0u8; 1];
+                    if self.inner.read(&mut byte)? == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "connection closed mid chunk data",
+                        ));
+                    }
+                    self.chunk = if left > 1 {
+                        ChunkState::DataCrlf(left - 1)
+                    } else {
+                        ChunkState::Size(Vec::new())
+                    };
+                }
+                ChunkState::TrailerCrlf(ref mut seen) => {
+                    // Trailer headers, if any, followed by the final
+                    // CRLF — read a byte at a time, with what's been seen
+                    // kept in the state so a read that would block resumes.
+                    loop {
+                        let mut byte = [0u8; 1];
+                        let n = self.inner.read(&mut byte)?;
+                        if n == 0 {
+                            return Err(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                "connection closed mid chunked trailer",
+                            ));
+                        }
+                        seen.push(byte[0]);
+                        if seen.ends_with(b"\r\n\r\n") || *seen == b"\r\n" {
+                            break;
+                        }
+                    }
+                    self.chunk = ChunkState::Done;
+                    write_frame(&mut self.out, FRAME_DATA, FLAG_END_STREAM, 1, &[]);
+                    return Ok(true);
+                }
+                ChunkState::Done => return Ok(true),
+            }
+        }
+    }
+}
+
+impl BodyMode {
+    fn clone(&self) -> BodyMode {
+        match self {
+            BodyMode::None => BodyMode::None,
+            BodyMode::Fixed(n) => BodyMode::Fixed(*n),
+            BodyMode::Chunked => BodyMode::Chunked,
+        }
+    }
+}
+
+impl<T: io::Read> io::Read for Replay<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.out.is_empty() {
+                let n = ::std::cmp::min(buf.len(), self.out.len());
+                buf[..n].copy_from_slice(&self.out[..n]);
+                self.out.advance(n);
+                return Ok(n);
+            }
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+impl<T: io::Write> io::Write for Replay<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Replay<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Replay<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn settings_header() -> String {
+        base64::encode_config(&[0, 0, 0, 0, 0, 0, 0, 0, 0], base64::URL_SAFE_NO_PAD)
+    }
+
+    #[test]
+    fn detects_h2c_upgrade() {
+        let req = format!(
+            "GET / HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: {}\r\n\r\n",
+            settings_header()
+        );
+        let upgrade = detect(req.as_bytes()).expect("should detect h2c upgrade");
+        assert!(upgrade.prefix.starts_with(PREFACE));
+        assert_eq!(upgrade.body, BodyMode::None);
+    }
+
+    #[test]
+    fn ignores_plain_request() {
+        let req = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert!(detect(&req[..]).is_none());
+    }
+
+    #[test]
+    fn ignores_upgrade_without_h2c() {
+        let req = b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+        assert!(detect(&req[..]).is_none());
+    }
+
+    #[test]
+    fn detects_fixed_length_body() {
+        let req = format!(
+            "POST /upload HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: {}\r\nContent-Length: 11\r\n\r\n",
+            settings_header()
+        );
+        let upgrade = detect(req.as_bytes()).expect("should detect h2c upgrade");
+        assert_eq!(upgrade.body, BodyMode::Fixed(11));
+        assert!(!upgrade.end_stream);
+    }
+
+    #[test]
+    fn replays_fixed_length_body_as_data_frame() {
+        let req_head = format!(
+            "POST /upload HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: {}\r\nContent-Length: 11\r\n\r\n",
+            settings_header()
+        );
+        let upgrade = detect(req_head.as_bytes()).expect("should detect h2c upgrade");
+        let prefix_len = upgrade.prefix.len();
+
+        // What's left on the wire after the head: the body, then the
+        // client's real (redundant) preface.
+        let mut wire = Vec::new();
+        wire.extend_from_slice(b"hello world");
+        wire.extend_from_slice(PREFACE);
+        wire.extend_from_slice(b"EXTRA");
+
+        let mut replay = Replay::new(upgrade, io::Cursor::new(wire));
+        let mut out = Vec::new();
+        replay.read_to_end(&mut out).unwrap();
+
+        // DATA frame (header + "hello world") should appear right after
+        // the synthesized prefix, and the duplicated client preface must
+        // not — only "EXTRA" survives after it.
+        assert!(out.ends_with(b"EXTRA"));
+        assert!(!out[prefix_len + 9 + "hello world".len()..].starts_with(PREFACE));
+        let data_payload = &out[prefix_len + 9..prefix_len + 9 + "hello world".len()];
+        assert_eq!(data_payload, b"hello world");
+    }
+
+    #[test]
+    fn replays_chunked_body_as_data_frames() {
+        let req_head = format!(
+            "POST /upload HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: {}\r\nTransfer-Encoding: chunked\r\n\r\n",
+            settings_header()
+        );
+        let upgrade = detect(req_head.as_bytes()).expect("should detect h2c upgrade");
+        assert_eq!(upgrade.body, BodyMode::Chunked);
+
+        let mut wire = Vec::new();
+        wire.extend_from_slice(b"5\r\nhello\r\n0\r\n\r\n");
+        wire.extend_from_slice(PREFACE);
+
+        let mut replay = Replay::new(upgrade, io::Cursor::new(wire));
+        let mut out = Vec::new();
+        replay.read_to_end(&mut out).unwrap();
+
+        // A DATA frame carrying "hello", followed by an empty
+        // END_STREAM DATA frame, and no leftover preface bytes.
+        assert!(out.windows(5).any(|w| w == b"hello"));
+        assert!(!out.ends_with(PREFACE));
+    }
+}
@@ -0,0 +1,340 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::{error, fmt};
+
+use futures::Poll;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use transport::Peek;
+
+/// The 12-byte signature that prefixes every PROXY protocol v2 header.
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+/// The longest a v1 (text) header is permitted to be, per spec.
+const V1_MAX_LEN: usize = 107;
+
+/// A successfully-parsed PROXY protocol header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    /// The number of bytes the header occupied in the stream, so the
+    /// caller can skip exactly that many bytes before handing the
+    /// connection on to protocol detection.
+    pub len: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProxyProtocolError {
+    /// Not enough bytes have been buffered yet to know whether a header
+    /// is present.
+    Incomplete,
+    /// The leading bytes are not a valid v1 or v2 PROXY protocol header.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ProxyProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProxyProtocolError::Incomplete => write!(f, "incomplete PROXY protocol header"),
+            ProxyProtocolError::Invalid(reason) => {
+                write!(f, "invalid PROXY protocol header: {}", reason)
+            }
+        }
+    }
+}
+
+impl error::Error for ProxyProtocolError {
+    fn description(&self) -> &str {
+        "invalid PROXY protocol header"
+    }
+}
+
+/// Attempts to parse a PROXY protocol (v1 or v2) header from the leading
+/// bytes of `buf`, which is expected to be the result of peeking the
+/// start of a freshly-accepted connection.
+///
+/// On success, returns the parsed source/destination addresses along with
+/// the number of bytes the header consumed. `buf` is never mutated; the
+/// caller is responsible for skipping `Header::len` bytes before resuming
+/// normal protocol detection.
+pub fn parse(buf: &[u8]) -> Result<Header, ProxyProtocolError> {
+    if buf.starts_with(&V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if buf.starts_with(b"PROXY ") {
+        parse_v1(buf)
+    } else if buf.len() < V2_SIGNATURE.len() {
+        Err(ProxyProtocolError::Incomplete)
+    } else {
+        Err(ProxyProtocolError::Invalid("missing PROXY protocol signature"))
+    }
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Header, ProxyProtocolError> {
+    if buf.len() < 16 {
+        return Err(ProxyProtocolError::Incomplete);
+    }
+
+    let ver_cmd = buf[12];
+    if ver_cmd >> 4 != 0x2 {
+        return Err(ProxyProtocolError::Invalid("unsupported PROXY protocol version"));
+    }
+    let command = ver_cmd & 0xF;
+
+    let fam_proto = buf[13];
+    let family = fam_proto >> 4;
+    let addr_len = u16::from(buf[14]) << 8 | u16::from(buf[15]);
+    let addr_len = addr_len as usize;
+
+    let total_len = 16 + addr_len;
+    if buf.len() < total_len {
+        return Err(ProxyProtocolError::Incomplete);
+    }
+
+    // A LOCAL command (e.g. a health check from the proxy itself) carries
+    // no meaningful address; there's nothing useful to rewrite.
+    if command == 0x0 {
+        return Err(ProxyProtocolError::Invalid("LOCAL command carries no address"));
+    }
+
+    let addrs = &buf[16..total_len];
+    let (src_ip, dst_ip, ports) = match family {
+        // AF_INET
+        0x1 => {
+            if addrs.len() < 12 {
+                return Err(ProxyProtocolError::Invalid("truncated IPv4 address block"));
+            }
+            let src = IpAddr::V4(Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]));
+            let dst = IpAddr::V4(Ipv4Addr::new(addrs[4], addrs[5], addrs[6], addrs[7]));
+            (src, dst, &addrs[8..12])
+        }
+        // AF_INET6
+        0x2 => {
+            if addrs.len() < 36 {
+                return Err(ProxyProtocolError::Invalid("truncated IPv6 address block"));
+            }
+            let mut src_octets = [0u8; 16];
+            let mut dst_octets = [0u8; 16];
+            src_octets.copy_from_slice(&addrs[0..16]);
+            dst_octets.copy_from_slice(&addrs[16..32]);
+            let src = IpAddr::V6(Ipv6Addr::from(src_octets));
+            let dst = IpAddr::V6(Ipv6Addr::from(dst_octets));
+            (src, dst, &addrs[32..36])
+        }
+        // AF_UNSPEC (e.g. UNKNOWN transport) — nothing we can rewrite to.
+        _ => return Err(ProxyProtocolError::Invalid("unsupported address family")),
+    };
+
+    let src_port = u16::from(ports[0]) << 8 | u16::from(ports[1]);
+    let dst_port = u16::from(ports[2]) << 8 | u16::from(ports[3]);
+
+    Ok(Header {
+        src: SocketAddr::new(src_ip, src_port),
+        dst: SocketAddr::new(dst_ip, dst_port),
+        len: total_len,
+    })
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Header, ProxyProtocolError> {
+    let scan_len = ::std::cmp::min(buf.len(), V1_MAX_LEN);
+    let line_end = match buf[..scan_len].windows(2).position(|w| w == b"\r\n") {
+        Some(pos) => pos,
+        None if buf.len() >= V1_MAX_LEN => {
+            return Err(ProxyProtocolError::Invalid("v1 header exceeds maximum length"));
+        }
+        None => return Err(ProxyProtocolError::Incomplete),
+    };
+
+    let line = ::std::str::from_utf8(&buf[..line_end])
+        .map_err(|_| ProxyProtocolError::Invalid("v1 header is not valid UTF-8"))?;
+
+    let mut parts = line.split(' ');
+    if parts.next() != Some("PROXY") {
+        return Err(ProxyProtocolError::Invalid("missing PROXY keyword"));
+    }
+
+    let proto = parts
+        .next()
+        .ok_or(ProxyProtocolError::Invalid("missing INET protocol"))?;
+    if proto == "UNKNOWN" {
+        return Err(ProxyProtocolError::Invalid("UNKNOWN protocol carries no address"));
+    }
+    if proto != "TCP4" && proto != "TCP6" {
+        return Err(ProxyProtocolError::Invalid("unsupported INET protocol"));
+    }
+
+    let src_ip: IpAddr = parts
+        .next()
+        .ok_or(ProxyProtocolError::Invalid("missing source address"))?
+        .parse()
+        .map_err(|_| ProxyProtocolError::Invalid("unparseable source address"))?;
+    let dst_ip: IpAddr = parts
+        .next()
+        .ok_or(ProxyProtocolError::Invalid("missing destination address"))?
+        .parse()
+        .map_err(|_| ProxyProtocolError::Invalid("unparseable destination address"))?;
+    let src_port: u16 = parts
+        .next()
+        .ok_or(ProxyProtocolError::Invalid("missing source port"))?
+        .parse()
+        .map_err(|_| ProxyProtocolError::Invalid("unparseable source port"))?;
+    let dst_port: u16 = parts
+        .next()
+        .ok_or(ProxyProtocolError::Invalid("missing destination port"))?
+        .parse()
+        .map_err(|_| ProxyProtocolError::Invalid("unparseable destination port"))?;
+    if parts.next().is_some() {
+        return Err(ProxyProtocolError::Invalid("trailing data after destination port"));
+    }
+
+    Ok(Header {
+        src: SocketAddr::new(src_ip, src_port),
+        dst: SocketAddr::new(dst_ip, dst_port),
+        len: line_end + 2,
+    })
+}
+
+/// Wraps an already-peeked `Io`, skipping the first `skip` bytes of the
+/// byte stream (the consumed PROXY protocol header) before anything else
+/// — the peer, protocol detection, and every other consumer — observes
+/// the connection.
+#[derive(Debug)]
+pub struct SkipPrefix<T> {
+    skip: usize,
+    inner: T,
+}
+
+impl<T> SkipPrefix<T> {
+    pub fn new(inner: T, skip: usize) -> Self {
+        SkipPrefix { skip, inner }
+    }
+}
+
+impl<T: io::Read> io::Read for SkipPrefix<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.skip > 0 {
+            let mut scratch = [0u8; 256];
+            let max = ::std::cmp::min(self.skip, scratch.len());
+            let n = self.inner.read(&mut scratch[..max])?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.skip -= n;
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<T: io::Write> io::Write for SkipPrefix<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for SkipPrefix<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for SkipPrefix<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+impl<T: Peek> Peek for SkipPrefix<T> {
+    fn poll_peek(&mut self) -> Poll<usize, io::Error> {
+        self.inner.poll_peek()
+    }
+
+    fn peeked(&self) -> &[u8] {
+        let buf = self.inner.peeked();
+        if self.skip >= buf.len() {
+            &[]
+        } else {
+            &buf[self.skip..]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_v1_tcp4() {
+        let buf = b"PROXY TCP4 10.0.0.1 10.0.0.2 5000 443\r\nGET / HTTP/1.1\r\n";
+        let header = parse(&buf[..]).expect("header should parse");
+        assert_eq!(header.src, "10.0.0.1:5000".parse().unwrap());
+        assert_eq!(header.dst, "10.0.0.2:443".parse().unwrap());
+        assert_eq!(header.len, 39);
+        assert_eq!(&buf[header.len..], &b"GET / HTTP/1.1\r\n"[..]);
+    }
+
+    #[test]
+    fn parses_v1_tcp6() {
+        let buf = b"PROXY TCP6 ::1 ::2 5000 443\r\n";
+        let header = parse(&buf[..]).expect("header should parse");
+        assert_eq!(header.src, "[::1]:5000".parse().unwrap());
+        assert_eq!(header.dst, "[::2]:443".parse().unwrap());
+        assert_eq!(header.len, buf.len());
+    }
+
+    #[test]
+    fn rejects_v1_unknown() {
+        let buf = b"PROXY UNKNOWN\r\n";
+        assert_eq!(
+            parse(&buf[..]),
+            Err(ProxyProtocolError::Invalid("UNKNOWN protocol carries no address"))
+        );
+    }
+
+    #[test]
+    fn incomplete_v1_without_crlf() {
+        let buf = b"PROXY TCP4 10.0.0.1 10.0.0.2 5000";
+        assert_eq!(parse(&buf[..]), Err(ProxyProtocolError::Incomplete));
+    }
+
+    #[test]
+    fn parses_v2_ipv4() {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(0x21); // version 2, command PROXY
+        buf.push(0x11); // AF_INET, STREAM
+        buf.extend_from_slice(&[0x00, 0x0C]); // address block length = 12
+        buf.extend_from_slice(&[10, 0, 0, 1]); // src
+        buf.extend_from_slice(&[10, 0, 0, 2]); // dst
+        buf.extend_from_slice(&[0x13, 0x88]); // src port 5000
+        buf.extend_from_slice(&[0x01, 0xBB]); // dst port 443
+        buf.extend_from_slice(b"GET / HTTP/1.1\r\n");
+
+        let header = parse(&buf).expect("header should parse");
+        assert_eq!(header.src, "10.0.0.1:5000".parse().unwrap());
+        assert_eq!(header.dst, "10.0.0.2:443".parse().unwrap());
+        assert_eq!(header.len, 28);
+    }
+
+    #[test]
+    fn rejects_v2_local_command() {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(0x20); // version 2, command LOCAL
+        buf.push(0x00);
+        buf.extend_from_slice(&[0x00, 0x00]);
+
+        assert_eq!(
+            parse(&buf),
+            Err(ProxyProtocolError::Invalid("LOCAL command carries no address"))
+        );
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let buf = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(
+            parse(&buf[..]),
+            Err(ProxyProtocolError::Invalid("missing PROXY protocol signature"))
+        );
+    }
+}
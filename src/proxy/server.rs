@@ -6,13 +6,18 @@ use indexmap::IndexSet;
 use std::{error, fmt};
 use std::net::SocketAddr;
 use tokio_connect::Connect;
+use tokio_io::io::write_all;
+use tokio_io::{AsyncRead, AsyncWrite};
 use tower_h2;
 
 use drain;
 use svc::{Make, Service, stack::MakeNewService};
 use transport::{tls, Connection, GetOriginalDst, Peek};
+use transport::tls::HasStatus;
 use proxy::http::glue::{HttpBody, HttpBodyNewSvc, HyperServerSvc};
+use proxy::h2c;
 use proxy::protocol::Protocol;
+use proxy::proxy_protocol::{self, ProxyProtocolError, SkipPrefix};
 use proxy::tcp;
 use super::Accept;
 
@@ -21,14 +26,19 @@ use super::Accept;
 /// This type can `serve` new connections, determine what protocol
 /// the connection is speaking, and route it to the corresponding
 /// service.
-pub struct Server<A, C, R, B, G>
+pub struct Server<A, C, R, B, G, J>
 where
     // Prepares a server transport, e.g. with telemetry.
     A: Make<Source, Error = ()> + Clone,
-    A::Value: Accept<Connection>,
-    // Prepares a client connecter (e.g. with telemetry, timeouts).
+    A::Value: Accept<SkipPrefix<Connection>>,
+    // Prepares a client connecter (e.g. with telemetry, timeouts). Once
+    // `Connect::Future` resolves, it yields the upstream transport paired
+    // with a `TransportMetadata` describing what the connect actually
+    // observed — e.g. whether TLS was established, and what it
+    // negotiated over ALPN — which `proxy::tcp::forward` only logs.
     C: Make<Source, Error = ()> + Clone,
-    C::Value: Connect,
+    C::Value: Connect<Connected = (J, TransportMetadata)>,
+    J: AsyncRead + AsyncWrite,
     // Prepares a route.
     R: Make<Source, Error = ()> + Clone,
     R::Value: Service<
@@ -40,6 +50,8 @@ where
     G: GetOriginalDst,
 {
     disable_protocol_detection_ports: IndexSet<u16>,
+    proxy_protocol_ports: IndexSet<u16>,
+    h2c_upgrade_ports: IndexSet<u16>,
     drain_signal: drain::Watch,
     get_orig_dst: G,
     h1: hyper::server::conn::Http,
@@ -51,6 +63,26 @@ where
     log: ::logging::Server,
 }
 
+/// Metadata about an outbound connection, observed once the `connect: C`
+/// stack's `Connect::Future` actually resolves — as opposed to `Source`,
+/// which is known up front, before any I/O occurs.
+///
+/// Following hyper's `Connect`/`Connected` redesign, a connector returns
+/// this alongside the transport instead of callers re-deriving the same
+/// facts by peeking the stream a second time. `connect: C` only backs
+/// `proxy::tcp::forward`'s plain byte-forwarding path, though, which has
+/// no per-request structure to attach this to — so today it's only ever
+/// logged there. Unlike `Source`, it isn't inserted into any extensions;
+/// `route: R`, the stack that actually handles HTTP requests, does its
+/// own upstream connecting outside of what this type has access to.
+#[derive(Clone, Debug, Default)]
+pub struct TransportMetadata {
+    /// Whether the upstream negotiated HTTP/2, e.g. via ALPN.
+    pub negotiated_h2: bool,
+    /// Whether the connection to the upstream was encrypted.
+    pub tls: bool,
+}
+
 /// Describes an accepted connection.
 #[derive(Clone, Debug)]
 pub struct Source {
@@ -103,16 +135,30 @@ impl Source {
    }
 }
 
-impl<A, C, R, B, G> Server<A, C, R, B, G>
+/// Maps a TLS connection's negotiated ALPN value to the application
+/// protocol it corresponds to, if any.
+///
+/// Returns `None` when no ALPN was negotiated (plaintext, or the client
+/// offered none), in which case the caller must fall back to detecting
+/// the protocol from the byte stream itself.
+fn alpn_protocol(status: &tls::Status) -> Option<Protocol> {
+    match status.negotiated_protocol()? {
+        b"h2" => Some(Protocol::Http2),
+        b"http/1.1" => Some(Protocol::Http1),
+        _ => None,
+    }
+}
+
+impl<A, C, R, B, G, J> Server<A, C, R, B, G, J>
 where
     A: Make<Source, Error = ()> + Clone,
-    A::Value: Accept<Connection>,
-    <A::Value as Accept<Connection>>::Io: Send + Peek + 'static,
+    A::Value: Accept<SkipPrefix<Connection>>,
+    <A::Value as Accept<SkipPrefix<Connection>>>::Io: Send + Peek + HasStatus + 'static,
     C: Make<Source, Error = ()> + Clone,
-    C::Value: Connect,
-    <C::Value as Connect>::Connected: Send + 'static,
+    C::Value: Connect<Connected = (J, TransportMetadata)>,
     <C::Value as Connect>::Future: Send + 'static,
     <C::Value as Connect>::Error: fmt::Debug + 'static,
+    J: AsyncRead + AsyncWrite + Send + 'static,
     R: Make<Source, Error = ()> + Clone,
     R::Value: Service<
         Request = http::Request<HttpBody>,
@@ -136,12 +182,16 @@ where
         connect: C,
         route: R,
         disable_protocol_detection_ports: IndexSet<u16>,
+        proxy_protocol_ports: IndexSet<u16>,
+        h2c_upgrade_ports: IndexSet<u16>,
         drain_signal: drain::Watch,
         h2_settings: h2::server::Builder,
     ) -> Self {
         let log = ::logging::Server::proxy(proxy_ctx, listen_addr);
         Server {
             disable_protocol_detection_ports,
+            proxy_protocol_ports,
+            h2c_upgrade_ports,
             drain_signal,
             get_orig_dst,
             h1: hyper::server::conn::Http::new(),
@@ -172,18 +222,14 @@ where
         let log = self.log.clone()
             .with_remote(remote_addr);
 
-        let source = Source {
+        let mut source = Source {
             remote: remote_addr,
             local: connection.local_addr().unwrap_or(self.listen_addr),
             orig_dst,
-            tls_status: connection.tls_status(),
+            tls_status: tls::Status::default(),
             _p: (),
         };
 
-        let io = self.accept.make(&source)
-            .expect("source must be acceptable")
-            .accept(connection);
-
         // We are using the port from the connection's SO_ORIGINAL_DST to
         // determine whether to skip protocol detection, not any port that
         // would be found after doing discovery.
@@ -193,28 +239,96 @@ where
             })
             .unwrap_or(false);
 
-        if disable_protocol_detection {
-            trace!("protocol detection disabled for {:?}", orig_dst);
-            let fwd = tcp::forward(io, &self.connect, &source);
-            let fut = self.drain_signal.watch(fwd, |_| {});
-            return log.future(Either::B(fut));
-        }
+        // Only trust a PROXY protocol header on listeners explicitly
+        // configured to expect one sitting behind a trusted L4 balancer;
+        // on every other port, treating client-controlled bytes as the
+        // real source address would be an easy spoof. The header, if
+        // present, arrives on the wire ahead of any TLS handshake, so it
+        // has to be stripped off the raw connection before `accept()`
+        // ever touches it — otherwise a listener combining PROXY
+        // protocol with TLS termination would have no chance to strip it
+        // once ALPN resolves the protocol. Skip the peek entirely when
+        // it isn't expected: the peer may never send bytes first, and
+        // peeking here could hang.
+        let proxy_protocol_enabled = orig_dst
+            .map(|addr| self.proxy_protocol_ports.contains(&addr.port()))
+            .unwrap_or(false);
+        let stripped = if proxy_protocol_enabled {
+            Either::A(connection.peek()
+                .map_err(|e| debug!("peek error: {}", e))
+                .and_then(move |connection| {
+                    match proxy_protocol::parse(connection.peeked()) {
+                        Ok(header) => {
+                            trace!("PROXY protocol: rewrote remote to {}", header.src);
+                            source.remote = header.src;
+                            Ok((SkipPrefix::new(connection, header.len), source))
+                        }
+                        Err(ProxyProtocolError::Incomplete) => {
+                            debug!("PROXY protocol: header incomplete after peek");
+                            Err(())
+                        }
+                        Err(e) => {
+                            warn!("PROXY protocol: {}", e);
+                            Err(())
+                        }
+                    }
+                }))
+        } else {
+            Either::B(future::ok((SkipPrefix::new(connection, 0), source)))
+        };
 
-        let detect_protocol = io.peek()
-            .map_err(|e| debug!("peek error: {}", e))
-            .map(|io| {
-                let p = Protocol::detect(io.peeked());
-                (p, io)
-            });
+        // PROXY-only ports aside, h2c upgrade is only trusted on ports
+        // explicitly opted in — meshed backends reachable by non-TLS,
+        // h2c-only clients.
+        let h2c_upgrade_enabled = orig_dst
+            .map(|addr| self.h2c_upgrade_ports.contains(&addr.port()))
+            .unwrap_or(false);
 
+        let accept = self.accept.clone();
         let h1 = self.h1.clone();
         let h2_settings = self.h2_settings.clone();
         let route = self.route.clone();
         let connect = self.connect.clone();
         let drain_signal = self.drain_signal.clone();
         let log_clone = log.clone();
-        let serve = detect_protocol
-            .and_then(move |(proto, io)| match proto {
+        let serve = stripped
+            .and_then(move |(connection, mut source)| {
+                let io = accept.make(&source)
+                    .expect("source must be acceptable")
+                    .accept(connection);
+
+                // ALPN, if any, is only negotiated once the TLS handshake
+                // -- performed by `accept()`, just above -- has actually
+                // run, so it can only be read off the accepted `io`, not
+                // the raw connection.
+                source.tls_status = io.tls_status();
+
+                if disable_protocol_detection {
+                    trace!("protocol detection disabled for {:?}", orig_dst);
+                    let fwd = tcp::forward(io, &connect, &source);
+                    return Either::A(drain_signal.watch(fwd, |_| {}));
+                }
+
+                // If the transport already negotiated ALPN (i.e. this is
+                // a TLS-terminated, meshed connection), that's an
+                // authoritative answer to "what protocol is this" and we
+                // can skip the byte-peeking detection entirely — the
+                // peer may never send application bytes first, so
+                // peeking here could hang.
+                let detect_protocol = match alpn_protocol(&source.tls_status) {
+                    Some(proto) => {
+                        trace!("selected {:?} via ALPN", proto);
+                        Either::A(future::ok((Some(proto), io, source)))
+                    }
+                    None => Either::B(io.peek()
+                        .map_err(|e| debug!("peek error: {}", e))
+                        .and_then(move |io| {
+                            let p = Protocol::detect(io.peeked());
+                            Ok((p, io, source))
+                        })),
+                };
+
+                Either::B(detect_protocol.and_then(move |(proto, io, source)| match proto {
                 None => Either::A({
                     trace!("did not detect protocol; forwarding TCP");
                     let fwd = tcp::forward(io, &connect, &source);
@@ -224,29 +338,82 @@ where
                 Some(proto) => Either::B(match proto {
                     Protocol::Http1 => Either::A({
                         trace!("detected HTTP/1");
-                        match route.make(&source) {
-                            Err(()) => Either::A({
-                                error!("failed to build HTTP/1 client");
-                                future::err(())
-                            }),
-                            Ok(s) => Either::B({
-                                let svc = HyperServerSvc::new(
-                                    s,
-                                    drain_signal.clone(),
-                                    log_clone.executor(),
-                                );
-                                // Enable support for HTTP upgrades (CONNECT and websockets).
-                                let conn = h1
-                                    .serve_connection(io, svc)
-                                    .with_upgrades();
-                                drain_signal
-                                    .watch(conn, |conn| {
-                                        conn.graceful_shutdown();
-                                    })
-                                    .map(|_| ())
-                                    .map_err(|e| trace!("http1 server error: {:?}", e))
-                            }),
-                        }
+
+                        let upgrade = if h2c_upgrade_enabled {
+                            h2c::detect(io.peeked())
+                        } else {
+                            None
+                        };
+
+                        let fut: Box<Future<Item = (), Error = ()> + Send> = match upgrade {
+                            Some(splice) => {
+                                trace!("upgrading HTTP/1 connection to h2c");
+                                let route = route.clone();
+                                let h2_settings = h2_settings.clone();
+                                let source = source.clone();
+                                let drain_signal = drain_signal.clone();
+                                let log_clone = log_clone.clone();
+                                Box::new(
+                                    write_all(
+                                        io,
+                                        &b"HTTP/1.1 101 Switching Protocols\r\n\
+                                           Connection: Upgrade\r\n\
+                                           Upgrade: h2c\r\n\r\n"[..],
+                                    ).map_err(|e| trace!("h2c upgrade response error: {:?}", e))
+                                        .and_then(move |(io, _)| {
+                                            // The HTTP/1.1 request head was
+                                            // only ever peeked off `io`, not
+                                            // consumed, so skip it before
+                                            // `Replay` starts reading what it
+                                            // thinks is the body/preface.
+                                            let io = SkipPrefix::new(io, splice.head_len());
+                                            let io = h2c::Replay::new(splice, io);
+                                            let new_service =
+                                                MakeNewService::new(route, source.clone());
+                                            let h2 = tower_h2::Server::new(
+                                                HttpBodyNewSvc::new(new_service),
+                                                h2_settings,
+                                                log_clone.executor(),
+                                            );
+                                            let serve = h2.serve_modified(
+                                                io,
+                                                move |r: &mut http::Request<()>| {
+                                                    r.extensions_mut().insert(source.clone());
+                                                },
+                                            );
+                                            drain_signal
+                                                .watch(serve, |conn| conn.graceful_shutdown())
+                                                .map_err(|e| trace!("h2 server error: {:?}", e))
+                                        }),
+                                )
+                            }
+                            None => match route.make(&source) {
+                                Err(()) => {
+                                    error!("failed to build HTTP/1 client");
+                                    Box::new(future::err(()))
+                                }
+                                Ok(s) => {
+                                    let svc = HyperServerSvc::new(
+                                        s,
+                                        drain_signal.clone(),
+                                        log_clone.executor(),
+                                    );
+                                    // Enable support for HTTP upgrades (CONNECT and websockets).
+                                    let conn = h1
+                                        .serve_connection(io, svc)
+                                        .with_upgrades();
+                                    Box::new(
+                                        drain_signal
+                                            .watch(conn, |conn| {
+                                                conn.graceful_shutdown();
+                                            })
+                                            .map(|_| ())
+                                            .map_err(|e| trace!("http1 server error: {:?}", e)),
+                                    )
+                                }
+                            },
+                        };
+                        fut
                     }),
                     Protocol::Http2 => Either::B({
                         trace!("detected HTTP/2");
@@ -264,8 +431,9 @@ where
                             .map_err(|e| trace!("h2 server error: {:?}", e))
                     }),
                 }),
-            });
+            }))
+        });
 
-        log.future(Either::A(serve))
+        log.future(serve)
     }
 }
@@ -0,0 +1,63 @@
+//! Plain TCP forwarding, used whenever a connection's protocol can't (or
+//! shouldn't) be detected — e.g. protocol detection is disabled for the
+//! listener, or no known protocol was found on the wire.
+
+use futures::{future::{self, Either}, Future};
+use std::fmt;
+use tokio_connect::Connect;
+use tokio_io::io::{copy, shutdown};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use svc::Make;
+use proxy::server::{Source, TransportMetadata};
+
+/// Connects to the upstream described by `source` and forwards bytes
+/// between it and `client_io` in both directions until either side
+/// closes.
+pub fn forward<I, C, J>(
+    client_io: I,
+    connect: &C,
+    source: &Source,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    I: AsyncRead + AsyncWrite + Send + 'static,
+    C: Make<Source, Error = ()>,
+    C::Value: Connect<Connected = (J, TransportMetadata)>,
+    <C::Value as Connect>::Future: Send + 'static,
+    <C::Value as Connect>::Error: fmt::Debug,
+    J: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let connect = match connect.make(source) {
+        Ok(connect) => connect,
+        Err(()) => {
+            error!("failed to build TCP connector");
+            return Either::A(future::err(()));
+        }
+    };
+
+    Either::B(
+        connect
+            .connect()
+            .map_err(|e| error!("TCP connect error: {:?}", e))
+            .and_then(move |(upstream_io, meta)| {
+                trace!(
+                    "connected to upstream; negotiated_h2={} tls={}",
+                    meta.negotiated_h2,
+                    meta.tls,
+                );
+
+                let (client_r, client_w) = client_io.split();
+                let (upstream_r, upstream_w) = upstream_io.split();
+
+                let client_to_upstream = copy(client_r, upstream_w)
+                    .and_then(|(_, _, upstream_w)| shutdown(upstream_w));
+                let upstream_to_client = copy(upstream_r, client_w)
+                    .and_then(|(_, _, client_w)| shutdown(client_w));
+
+                client_to_upstream
+                    .join(upstream_to_client)
+                    .map(|_| ())
+                    .map_err(|e| error!("TCP forward error: {}", e))
+            }),
+    )
+}
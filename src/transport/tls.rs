@@ -0,0 +1,82 @@
+use bytes::Bytes;
+
+/// An application protocol negotiated over ALPN during a TLS handshake
+/// (e.g. `h2` or `http/1.1`).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NegotiatedProtocol(Bytes);
+
+impl NegotiatedProtocol {
+    pub fn new<B: Into<Bytes>>(protocol: B) -> Self {
+        NegotiatedProtocol(protocol.into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Whether an accepted connection was terminated with TLS and, if so,
+/// what (if anything) it negotiated.
+///
+/// Unlike `Source`, which is known as soon as a connection is accepted,
+/// this is only meaningful once the TLS handshake — if any — has
+/// actually completed.
+#[derive(Clone, Debug)]
+pub enum Status {
+    /// The connection is plaintext; no TLS handshake occurred.
+    Disabled,
+    /// The connection was terminated with TLS. Carries the
+    /// ALPN-negotiated application protocol, if the peer offered one the
+    /// server recognized.
+    Established(Option<NegotiatedProtocol>),
+}
+
+impl Status {
+    /// The ALPN-negotiated application protocol, if any.
+    ///
+    /// Returns `None` both when TLS wasn't used at all and when it was
+    /// used but no protocol was negotiated — callers that need to tell
+    /// these apart should match on `Status` directly.
+    pub fn negotiated_protocol(&self) -> Option<&[u8]> {
+        match self {
+            Status::Disabled => None,
+            Status::Established(protocol) => protocol.as_ref().map(NegotiatedProtocol::as_bytes),
+        }
+    }
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status::Disabled
+    }
+}
+
+/// Exposes a transport's `Status`, once it's known.
+///
+/// Implemented by an `Accept::Io`, so callers can read the real,
+/// post-handshake ALPN result off the accepted transport rather than
+/// off the pre-handshake connection, where it's not yet meaningful.
+pub trait HasStatus {
+    fn tls_status(&self) -> Status;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_has_no_negotiated_protocol() {
+        assert_eq!(Status::Disabled.negotiated_protocol(), None);
+    }
+
+    #[test]
+    fn established_without_alpn_has_no_negotiated_protocol() {
+        assert_eq!(Status::Established(None).negotiated_protocol(), None);
+    }
+
+    #[test]
+    fn established_with_alpn_reports_it() {
+        let status = Status::Established(Some(NegotiatedProtocol::new(&b"h2"[..])));
+        assert_eq!(status.negotiated_protocol(), Some(&b"h2"[..]));
+    }
+}